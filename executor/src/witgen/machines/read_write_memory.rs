@@ -0,0 +1,277 @@
+use std::collections::{BTreeMap, HashMap};
+
+use ast::{
+    analyzed::{
+        AlgebraicExpression as Expression, AlgebraicReference, Identity, IdentityKind, PolyID,
+        PolynomialType,
+    },
+    parsed::SelectedExpressions,
+};
+use number::FieldElement;
+
+use crate::witgen::{
+    affine_expression::AffineExpression, util::try_to_simple_poly, EvalResult, EvalValue,
+    FixedData, IncompleteCause, MutableState, QueryCallback,
+};
+
+use super::{FixedLookup, Machine};
+
+/// A RAM machine: unlike `WriteOnceMemory`, the same address can be written to and
+/// read from repeatedly over the course of the program.
+/// In the simplest case, it looks like this:
+/// ```pil
+/// let addr;
+/// let step;
+/// let value;
+/// let is_write;
+/// // Stores `X` at address `A` at time `S`.
+/// instr mstore A, S, X { {A, S, X, 1} in {addr, step, value, is_write} }
+/// // Loads the value at address `A` at time `S`. If the address has never been
+/// // written to, the prover can choose a value.
+/// instr mload A, S -> X { {A, S, X, 0} in {addr, step, value, is_write} }
+/// ```
+pub struct ReadWriteMemory<'a, T: FieldElement> {
+    /// The fixed data
+    fixed_data: &'a FixedData<'a, T>,
+    /// The right-hand side of the connecting identity
+    /// (if there are several, they must all be the same)
+    rhs: &'a SelectedExpressions<Expression<T>>,
+    /// The witness polynomials exposed on the RHS
+    addr_poly: PolyID,
+    step_poly: PolyID,
+    value_poly: PolyID,
+    is_write_poly: PolyID,
+    /// All operations seen so far, in the order they were processed: (addr, step, value, is_write)
+    trace: Vec<(T, T, T, bool)>,
+    /// The most recently recorded value for each address, used to resolve reads.
+    last_value: BTreeMap<T, T>,
+}
+
+impl<'a, T: FieldElement> ReadWriteMemory<'a, T> {
+    pub fn try_new(
+        fixed_data: &'a FixedData<'a, T>,
+        connecting_identities: &[&'a Identity<Expression<T>>],
+        identities: &[&Identity<Expression<T>>],
+    ) -> Option<Self> {
+        if !identities.is_empty() {
+            return None;
+        }
+
+        let rhs = &connecting_identities[0].right;
+        if !connecting_identities.iter().all(|i| i.right == *rhs) {
+            return None;
+        }
+
+        if rhs.selector.is_some() {
+            return None;
+        }
+
+        // The RHS must consist of exactly four witness polynomials, in order:
+        // address, step, value, is_write.
+        let [addr_poly, step_poly, value_poly, is_write_poly] = rhs
+            .expressions
+            .iter()
+            .map(|e| try_to_simple_poly(e))
+            .collect::<Option<Vec<_>>>()?
+            .into_iter()
+            .filter(|p| p.poly_id.ptype == PolynomialType::Committed)
+            .map(|p| {
+                assert!(!p.next);
+                p.poly_id
+            })
+            .collect::<Vec<_>>()
+            .try_into()
+            .ok()?;
+
+        Some(Self {
+            fixed_data,
+            rhs,
+            addr_poly,
+            step_poly,
+            value_poly,
+            is_write_poly,
+            trace: vec![],
+            last_value: BTreeMap::new(),
+        })
+    }
+
+    fn process_plookup_internal(
+        &mut self,
+        left: &[AffineExpression<&'a AlgebraicReference, T>],
+        right: &'a SelectedExpressions<Expression<T>>,
+    ) -> EvalResult<'a, T> {
+        let by_poly_id = right
+            .expressions
+            .iter()
+            .zip(left.iter())
+            .map(|(r, l)| (try_to_simple_poly(r).unwrap().poly_id, l))
+            .collect::<HashMap<_, _>>();
+
+        let Some(addr) = by_poly_id[&self.addr_poly].constant_value() else {
+            return Ok(EvalValue::incomplete(
+                IncompleteCause::NonConstantRequiredArgument("addr"),
+            ));
+        };
+        let Some(step) = by_poly_id[&self.step_poly].constant_value() else {
+            return Ok(EvalValue::incomplete(
+                IncompleteCause::NonConstantRequiredArgument("step"),
+            ));
+        };
+        let Some(is_write) = by_poly_id[&self.is_write_poly].constant_value() else {
+            return Ok(EvalValue::incomplete(
+                IncompleteCause::NonConstantRequiredArgument("is_write"),
+            ));
+        };
+        let is_write = !is_write.is_zero();
+
+        let value_expr = by_poly_id[&self.value_poly];
+        let stored = (!is_write)
+            .then(|| self.last_value.get(&addr).copied())
+            .flatten();
+        let provided = value_expr.constant_value();
+
+        let Some(value) = resolve_access_value(stored, provided) else {
+            // No value known yet (a read of a never-written address, or a write whose
+            // value isn't provided) -> retry later.
+            return Ok(EvalValue::incomplete(
+                IncompleteCause::NonConstantRequiredArgument("value"),
+            ));
+        };
+        // A previously stored value must be returned as-is; update the LHS if needed.
+        let updates = match stored {
+            Some(stored) => (value_expr.clone() - stored.into()).solve()?.constraints,
+            None => vec![],
+        };
+
+        self.last_value.insert(addr, value);
+        self.trace.push((addr, step, value, is_write));
+
+        Ok(EvalValue::complete(updates))
+    }
+}
+
+/// Decides what value a memory access resolves to, given whatever was previously
+/// recorded for the address (`stored`, only considered for reads) and whatever
+/// value the caller's side of the lookup already provides (`provided`). A stored
+/// value always wins (a read must return the last write); with nothing stored, the
+/// provided value is used (a write, or a read of a fresh address whose value the
+/// caller already gives). Returns `None` only when neither is known: a read of a
+/// fresh address, which the prover is free to choose a value for later, or a write
+/// whose value is not known yet.
+fn resolve_access_value<T: FieldElement>(stored: Option<T>, provided: Option<T>) -> Option<T> {
+    stored.or(provided)
+}
+
+impl<'a, T: FieldElement> Machine<'a, T> for ReadWriteMemory<'a, T> {
+    fn process_plookup<'b, Q: QueryCallback<T>>(
+        &mut self,
+        // Phase-gated witness generation is out of scope here, same as in
+        // `WriteOnceMemory`: it needs a phase index and challenge map on
+        // `MutableState`, which this crate's definitions don't have.
+        _mutable_state: &'b mut MutableState<'a, 'b, T, Q>,
+        kind: IdentityKind,
+        left: &[AffineExpression<&'a AlgebraicReference, T>],
+        // The LHS selector is not evaluated, unlike in `WriteOnceMemory`: every access
+        // is processed as unconditional, including one whose selector is zero.
+        _left_selector: Option<&AffineExpression<&'a AlgebraicReference, T>>,
+        right: &'a SelectedExpressions<Expression<T>>,
+    ) -> Option<EvalResult<'a, T>> {
+        (right == self.rhs && kind == IdentityKind::Plookup)
+            .then(|| self.process_plookup_internal(left, right))
+    }
+
+    fn take_witness_col_values<'b, Q: QueryCallback<T>>(
+        &mut self,
+        _fixed_lookup: &'b mut FixedLookup<T>,
+        _query_callback: &'b mut Q,
+    ) -> HashMap<String, Vec<T>> {
+        let mut sorted = self.trace.clone();
+        sorted.sort_by_key(|&(addr, step, _, _)| (addr, step));
+
+        let mut addr_col = Vec::with_capacity(sorted.len());
+        let mut step_col = Vec::with_capacity(sorted.len());
+        let mut value_col = Vec::with_capacity(sorted.len());
+        let mut is_write_col = Vec::with_capacity(sorted.len());
+        let mut addr_changed_col = Vec::with_capacity(sorted.len());
+        let mut step_diff_col = Vec::with_capacity(sorted.len());
+
+        let mut previous: Option<(T, T)> = None;
+        for (addr, step, value, is_write) in sorted {
+            let addr_changed = previous.map_or(true, |(prev_addr, _)| prev_addr != addr);
+            let step_diff = match (addr_changed, previous) {
+                (false, Some((_, prev_step))) => step - prev_step,
+                _ => T::zero(),
+            };
+
+            addr_col.push(addr);
+            step_col.push(step);
+            value_col.push(value);
+            is_write_col.push(if is_write { T::one() } else { T::zero() });
+            addr_changed_col.push(if addr_changed { T::one() } else { T::zero() });
+            step_diff_col.push(step_diff);
+
+            previous = Some((addr, step));
+        }
+
+        // Pad to the full degree by repeating the last operation, which keeps
+        // `addr_changed` and `step_diff` trivially satisfied on the padding rows.
+        let degree = self.fixed_data.degree as usize;
+        while addr_col.len() < degree {
+            addr_col.push(addr_col.last().copied().unwrap_or_default());
+            step_col.push(step_col.last().copied().unwrap_or_default());
+            value_col.push(value_col.last().copied().unwrap_or_default());
+            is_write_col.push(T::zero());
+            addr_changed_col.push(T::zero());
+            step_diff_col.push(T::zero());
+        }
+
+        [
+            (
+                self.fixed_data.column_name(&self.addr_poly).to_string(),
+                addr_col,
+            ),
+            (
+                self.fixed_data.column_name(&self.step_poly).to_string(),
+                step_col,
+            ),
+            (
+                self.fixed_data.column_name(&self.value_poly).to_string(),
+                value_col,
+            ),
+            (
+                self.fixed_data.column_name(&self.is_write_poly).to_string(),
+                is_write_col,
+            ),
+            ("addr_changed".to_string(), addr_changed_col),
+            ("step_diff".to_string(), step_diff_col),
+        ]
+        .into_iter()
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::resolve_access_value;
+    use number::{FieldElement, GoldilocksField as F};
+
+    #[test]
+    fn read_returns_last_write() {
+        let last_write = F::from(42);
+        assert_eq!(
+            resolve_access_value(Some(last_write), None),
+            Some(last_write)
+        );
+    }
+
+    #[test]
+    fn fresh_address_read_lets_prover_choose() {
+        assert_eq!(resolve_access_value::<F>(None, None), None);
+    }
+
+    #[test]
+    fn write_records_the_provided_value() {
+        let written = F::from(7);
+        assert_eq!(resolve_access_value(None, Some(written)), Some(written));
+    }
+}
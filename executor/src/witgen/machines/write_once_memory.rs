@@ -29,9 +29,15 @@ use super::{FixedLookup, Machine};
 /// // of an input.
 /// instr mload X -> Y { {X, Y} in {ADDR, v} }
 /// ```
+/// The same connection can also be expressed as a `Permutation` identity (`is`
+/// instead of `in`) when every row is known to be accessed at most once; this is
+/// cheaper to prove but panics in `take_witness_col_values` if coverage is partial.
 pub struct WriteOnceMemory<'a, T: FieldElement> {
     /// The fixed data
     fixed_data: &'a FixedData<'a, T>,
+    /// Whether this connects via a `Plookup` (containment) or `Permutation`
+    /// (one-to-one) identity; all connecting identities must agree on this.
+    kind: IdentityKind,
     /// The right-hand side of the connecting identity
     /// (if there are several, they must all be the same)
     rhs: &'a SelectedExpressions<Expression<T>>,
@@ -41,6 +47,12 @@ pub struct WriteOnceMemory<'a, T: FieldElement> {
     key_to_index: BTreeMap<Vec<T>, DegreeType>,
     /// The memory content
     data: BTreeMap<DegreeType, Vec<Option<T>>>,
+    /// If set, the name of the column that should count how many times each row of
+    /// `key_to_index` was looked up, for consumption by a LogUp-style backend.
+    multiplicity_column_name: Option<String>,
+    /// The number of times each row index has been looked up so far; only counted
+    /// when `multiplicity_column_name` is set, otherwise left empty.
+    multiplicities: BTreeMap<DegreeType, T>,
 }
 
 impl<'a, T: FieldElement> WriteOnceMemory<'a, T> {
@@ -48,20 +60,40 @@ impl<'a, T: FieldElement> WriteOnceMemory<'a, T> {
         fixed_data: &'a FixedData<'a, T>,
         connecting_identities: &[&'a Identity<Expression<T>>],
         identities: &[&Identity<Expression<T>>],
+        with_multiplicity: bool,
     ) -> Option<Self> {
         if !identities.is_empty() {
             return None;
         }
 
-        let rhs = &connecting_identities[0].right;
-        if !connecting_identities.iter().all(|i| i.right == *rhs) {
+        let kind = connecting_identities[0].kind;
+        if !matches!(kind, IdentityKind::Plookup | IdentityKind::Permutation) {
             return None;
         }
 
-        if rhs.selector.is_some() {
+        let rhs = &connecting_identities[0].right;
+        if !connecting_identities
+            .iter()
+            .all(|i| i.right == *rhs && i.kind == kind)
+        {
             return None;
         }
 
+        // A selector on the RHS gates which table rows are part of the connection; only
+        // a simple fixed-column selector is supported, since evaluating anything more
+        // complex per row would need a general expression evaluator this machine doesn't
+        // have. Rows it selects out are simply excluded from `key_to_index` below.
+        let rhs_selector_poly = match &rhs.selector {
+            None => None,
+            Some(selector) => {
+                let poly = try_to_simple_poly(selector)?;
+                if poly.next || poly.poly_id.ptype != PolynomialType::Constant {
+                    return None;
+                }
+                Some(poly.poly_id)
+            }
+        };
+
         let rhs_polys = rhs
             .expressions
             .iter()
@@ -90,32 +122,63 @@ impl<'a, T: FieldElement> WriteOnceMemory<'a, T> {
             })
             .collect::<Vec<_>>();
 
-        let mut key_to_index = BTreeMap::new();
-        for row in 0..fixed_data.degree {
-            let key = key_polys
+        let keys = (0..fixed_data.degree)
+            .map(|row| {
+                key_polys
+                    .iter()
+                    .map(|k| fixed_data.fixed_cols[k].values[row as usize])
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let selector_values = rhs_selector_poly
+            .as_ref()
+            .map(|p| fixed_data.fixed_cols[p].values.clone());
+        let key_to_index = build_key_to_index(&keys, selector_values.as_deref())?;
+
+        // LogUp backends identify the accumulator by name; derive it from the key
+        // columns so that e.g. `ADDR` gets a companion `ADDR_multiplicity` column.
+        let multiplicity_column_name = with_multiplicity.then(|| {
+            let names = key_polys
                 .iter()
-                .map(|k| fixed_data.fixed_cols[k].values[row as usize])
-                .collect::<Vec<_>>();
-            if key_to_index.insert(key, row).is_some() {
-                // Duplicate keys, can't be a write-once memory
-                return None;
-            }
-        }
+                .map(|p| fixed_data.column_name(p))
+                .collect::<Vec<_>>()
+                .join("_");
+            format!("{names}_multiplicity")
+        });
 
         Some(Self {
             fixed_data,
+            kind,
             rhs,
             value_polys,
             key_to_index,
             data: BTreeMap::new(),
+            multiplicity_column_name,
+            multiplicities: BTreeMap::new(),
         })
     }
 
     fn process_plookup_internal(
         &mut self,
         left: &[AffineExpression<&'a AlgebraicReference, T>],
+        left_selector: Option<&AffineExpression<&'a AlgebraicReference, T>>,
         right: &'a SelectedExpressions<Expression<T>>,
     ) -> EvalResult<'a, T> {
+        match left_selector.map(|s| s.constant_value()) {
+            // No selector: the lookup is unconditional, as before.
+            None => {}
+            // Selector resolved to false: this is a no-op, nothing to store.
+            Some(Some(v)) if v.is_zero() => return Ok(EvalValue::complete(vec![])),
+            // Selector resolved to true: proceed as if there was no selector.
+            Some(Some(_)) => {}
+            // Selector not yet known: retry once the caller has pinned it down.
+            Some(None) => {
+                return Ok(EvalValue::incomplete(
+                    IncompleteCause::NonConstantRequiredArgument("selector"),
+                ))
+            }
+        }
+
         let (key_expressions, value_expressions): (Vec<_>, Vec<_>) = left
             .iter()
             .zip(right.expressions.iter())
@@ -148,6 +211,21 @@ impl<'a, T: FieldElement> WriteOnceMemory<'a, T> {
             EvalError::from(format!("Key {:?} not found in write-once memory", key))
         })?;
 
+        // Whether this index was already fully resolved by a previous, distinct access,
+        // as opposed to merely being in progress (partially solved, about to be retried).
+        let was_already_resolved = self
+            .data
+            .get(&index)
+            .is_some_and(|values| values.iter().all(Option::is_some));
+
+        // A retry of the same not-yet-solved access calls back into this function again
+        // before it is resolved; only a second, already-resolved access is a real conflict.
+        if self.kind == IdentityKind::Permutation && was_already_resolved {
+            return Err(EvalError::from(format!(
+                "Key {key:?} accessed more than once in a permutation argument"
+            )));
+        }
+
         // If there is an externally provided memory value, use it
         let external_witness_value = self
             .value_polys
@@ -186,6 +264,16 @@ impl<'a, T: FieldElement> WriteOnceMemory<'a, T> {
         let is_complete = !values.contains(&None);
         self.data.insert(index, values);
 
+        // Count every access once it resolves. A call only ever becomes complete once: an
+        // incomplete call gets retried by the solver until it resolves, so gating on
+        // `is_complete` alone (not `was_already_resolved`, which can't tell a same-call retry
+        // apart from a genuinely new access to an already-resolved key) already counts each
+        // retry at most once, while still counting every distinct access to the same row.
+        if is_complete && self.multiplicity_column_name.is_some() {
+            let count = self.multiplicities.entry(index).or_insert_with(T::zero);
+            *count = *count + T::one();
+        }
+
         match is_complete {
             true => Ok(EvalValue::complete(updates)),
             false => Ok(EvalValue::incomplete_with_constraints(
@@ -199,13 +287,18 @@ impl<'a, T: FieldElement> WriteOnceMemory<'a, T> {
 impl<'a, T: FieldElement> Machine<'a, T> for WriteOnceMemory<'a, T> {
     fn process_plookup<'b, Q: QueryCallback<T>>(
         &mut self,
+        // Phase-gated witness generation (this machine deferring to a later phase once
+        // challenges are available) is out of scope here: it needs a phase index and
+        // challenge map on `MutableState` plus a `ChallengeNotYetAvailable` cause, none of
+        // which exist in this crate's `Machine`/`MutableState` definitions.
         _mutable_state: &'b mut MutableState<'a, 'b, T, Q>,
         kind: IdentityKind,
         left: &[AffineExpression<&'a AlgebraicReference, T>],
+        left_selector: Option<&AffineExpression<&'a AlgebraicReference, T>>,
         right: &'a SelectedExpressions<Expression<T>>,
     ) -> Option<EvalResult<'a, T>> {
-        (right == self.rhs && kind == IdentityKind::Plookup)
-            .then(|| self.process_plookup_internal(left, right))
+        (right == self.rhs && kind == self.kind)
+            .then(|| self.process_plookup_internal(left, left_selector, right))
     }
 
     fn take_witness_col_values<'b, Q: QueryCallback<T>>(
@@ -213,6 +306,22 @@ impl<'a, T: FieldElement> Machine<'a, T> for WriteOnceMemory<'a, T> {
         _fixed_lookup: &'b mut FixedLookup<T>,
         _query_callback: &'b mut Q,
     ) -> HashMap<String, Vec<T>> {
+        if self.kind == IdentityKind::Permutation {
+            // A permutation argument requires every row to be matched exactly once,
+            // so every index that could be looked up must have been assigned a value.
+            assert_eq!(
+                self.data.len(),
+                self.key_to_index.len(),
+                "Not all rows were covered by the permutation"
+            );
+            assert!(
+                self.data
+                    .values()
+                    .all(|values| values.iter().all(Option::is_some)),
+                "Not all values were committed for the permutation"
+            );
+        }
+
         self.value_polys
             .iter()
             .enumerate()
@@ -234,6 +343,70 @@ impl<'a, T: FieldElement> Machine<'a, T> for WriteOnceMemory<'a, T> {
                     });
                 (self.fixed_data.column_name(poly).to_string(), column)
             })
+            .chain(self.multiplicity_column_name.clone().map(|name| {
+                let mut column = vec![T::zero(); self.fixed_data.degree as usize];
+                for (&row, &count) in self.multiplicities.iter() {
+                    column[row as usize] = count;
+                }
+                (name, column)
+            }))
             .collect()
     }
 }
+
+/// Builds the key-to-row-index map for a write-once memory: `keys[row]` is the row's key
+/// tuple, and `selector_values[row]`, if given, excludes the row from the map when zero.
+/// Returns `None` if two selected rows share a key, since that can't be a write-once memory.
+fn build_key_to_index<T: FieldElement>(
+    keys: &[Vec<T>],
+    selector_values: Option<&[T]>,
+) -> Option<BTreeMap<Vec<T>, DegreeType>> {
+    let mut key_to_index = BTreeMap::new();
+    for (row, key) in keys.iter().enumerate() {
+        if let Some(selector_values) = selector_values {
+            if selector_values[row].is_zero() {
+                continue;
+            }
+        }
+        if key_to_index
+            .insert(key.clone(), row as DegreeType)
+            .is_some()
+        {
+            return None;
+        }
+    }
+    Some(key_to_index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::build_key_to_index;
+    use number::GoldilocksField as F;
+
+    #[test]
+    fn unselected_rows_are_excluded() {
+        let keys = vec![vec![F::from(0)], vec![F::from(1)], vec![F::from(2)]];
+        let selector_values = [F::from(1), F::from(0), F::from(1)];
+        let key_to_index = build_key_to_index(&keys, Some(&selector_values)).unwrap();
+
+        assert_eq!(key_to_index.len(), 2);
+        assert_eq!(key_to_index.get(&vec![F::from(0)]), Some(&0));
+        assert_eq!(key_to_index.get(&vec![F::from(1)]), None);
+        assert_eq!(key_to_index.get(&vec![F::from(2)]), Some(&2));
+    }
+
+    #[test]
+    fn duplicate_key_among_selected_rows_is_rejected() {
+        let keys = vec![vec![F::from(5)], vec![F::from(5)]];
+        assert!(build_key_to_index(&keys, None).is_none());
+    }
+
+    #[test]
+    fn duplicate_key_is_allowed_if_one_copy_is_unselected() {
+        let keys = vec![vec![F::from(5)], vec![F::from(5)]];
+        let selector_values = [F::from(1), F::from(0)];
+        let key_to_index = build_key_to_index(&keys, Some(&selector_values)).unwrap();
+
+        assert_eq!(key_to_index.get(&vec![F::from(5)]), Some(&0));
+    }
+}